@@ -0,0 +1,310 @@
+use super::{
+    error::{BatchLengthMismatch, Closed, ServiceError},
+    message::{Message, Tx},
+};
+use std::sync::{Arc, Mutex};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Sleep};
+use tower_service::Service;
+
+/// The response type produced by a single item of a flushed batch.
+type Item<T, Request> = <<T as Service<Vec<Request>>>::Response as IntoIterator>::Item;
+
+pin_project_lite::pin_project! {
+    /// Task that accumulates requests into batches and dispatches each batch
+    /// to the inner service in a single call, fanning the results back out
+    /// to the individual callers. This type should not be used directly,
+    /// instead `Batch` requires an `Executor` that can accept this task.
+    ///
+    /// Like `buffer::Worker`, this type is `pub` in a private module so it
+    /// can appear in public API signatures without being nameable or
+    /// implementable by consumers of the library ("sealed" pattern).
+    #[derive(Debug)]
+    pub struct Worker<T, Request>
+    where
+        T: Service<Vec<Request>>,
+    {
+        rx: mpsc::Receiver<Message<Request, Item<T, Request>>>,
+        service: T,
+        finish: bool,
+        failed: Option<ServiceError>,
+        handle: Handle,
+        max_items: usize,
+        max_latency: Duration,
+        batch: Vec<Message<Request, Item<T, Request>>>,
+        deadline: Option<Pin<Box<Sleep>>>,
+        flushing: Option<(Pin<Box<T::Future>>, Vec<Tx<Item<T, Request>>>)>,
+    }
+}
+
+/// Get the error out.
+#[derive(Debug)]
+pub(crate) struct Handle {
+    inner: Arc<Mutex<Option<ServiceError>>>,
+}
+
+impl<T, Request> Worker<T, Request>
+where
+    T: Service<Vec<Request>>,
+    T::Response: IntoIterator,
+    T::Error: Into<crate::BoxError>,
+{
+    /// # Panics
+    ///
+    /// Panics if `max_items` is `0`: a batch could never be filled (or ever
+    /// flushed, since nothing would arm the latency deadline), so every
+    /// caller would hang forever.
+    pub(crate) fn new(
+        service: T,
+        rx: mpsc::Receiver<Message<Request, Item<T, Request>>>,
+        max_items: usize,
+        max_latency: Duration,
+    ) -> (Handle, Worker<T, Request>) {
+        assert!(max_items >= 1, "max_items must be at least 1");
+
+        let handle = Handle {
+            inner: Arc::new(Mutex::new(None)),
+        };
+
+        let worker = Worker {
+            finish: false,
+            failed: None,
+            rx,
+            service,
+            handle: handle.clone(),
+            max_items,
+            max_latency,
+            batch: Vec::new(),
+            deadline: None,
+            flushing: None,
+        };
+
+        (handle, worker)
+    }
+
+    /// Return the next queued Message that hasn't been canceled.
+    fn poll_next_msg(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Message<Request, Item<T, Request>>>> {
+        if self.finish {
+            // We've already received None and are shutting down
+            return Poll::Ready(None);
+        }
+
+        tracing::trace!("worker polling for next message");
+        while let Some(msg) = ready!(Pin::new(&mut self.rx).poll_recv(cx)) {
+            if !msg.tx.is_closed() {
+                tracing::trace!("adding request to batch");
+                return Poll::Ready(Some(msg));
+            }
+            // Otherwise, request is canceled, so pop the next one.
+            tracing::trace!("dropping cancelled request");
+        }
+
+        Poll::Ready(None)
+    }
+
+    /// Greedily pull any messages that are immediately available into the
+    /// pending batch, arming the flush deadline on the first item. Returns
+    /// `Ready` once the batch is full or the source is exhausted, and
+    /// `Pending` when there is nothing left to pull without blocking.
+    fn poll_fill_batch(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while self.batch.len() < self.max_items {
+            match self.poll_next_msg(cx) {
+                Poll::Ready(Some(msg)) => {
+                    if self.batch.is_empty() {
+                        self.deadline = Some(Box::pin(sleep(self.max_latency)));
+                    }
+                    self.batch.push(msg);
+                }
+                Poll::Ready(None) => {
+                    self.finish = true;
+                    return Poll::Ready(());
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        Poll::Ready(())
+    }
+
+    fn failed(&mut self, error: crate::BoxError) {
+        // See the equivalent comment in `buffer::Worker::failed`: we first
+        // expose the error, then close the channel, then notify outstanding
+        // requests, so that no caller can observe a channel send succeed
+        // after the error has already been surfaced.
+        let error = ServiceError::new(error);
+
+        let mut inner = self.handle.inner.lock().unwrap();
+
+        if inner.is_some() {
+            // Future::poll was called after we've already errored out!
+            return;
+        }
+
+        *inner = Some(error.clone());
+        drop(inner);
+
+        self.rx.close();
+        self.failed = Some(error);
+    }
+
+    /// Fail every message currently in the pending batch with the worker's
+    /// stored error, used once the inner service has errored out.
+    fn fail_batch(&mut self) {
+        let error = self
+            .failed
+            .clone()
+            .expect("fail_batch called without a stored error");
+        for msg in self.batch.drain(..) {
+            let _ = msg.tx.send(Err(error.clone()));
+        }
+        self.deadline = None;
+    }
+
+    /// Zip the results of a flushed batch back out to each caller's
+    /// `oneshot`, in the same order the requests were submitted in.
+    fn dispatch_result(senders: Vec<Tx<Item<T, Request>>>, result: Result<T::Response, T::Error>) {
+        match result {
+            Ok(responses) => {
+                let responses: Vec<_> = responses.into_iter().collect();
+                let mismatched = responses.len() != senders.len();
+                if mismatched {
+                    // The inner service broke the "aligned to input order"
+                    // contract. Report it loudly *and* make sure every
+                    // caller left over by the mismatch gets a diagnosable
+                    // error instead of silently falling off a `zip` and
+                    // seeing a bare, causeless `Closed`.
+                    tracing::error!(
+                        batch.len = senders.len(),
+                        responses.len = responses.len(),
+                        "batched service returned a response collection whose length doesn't \
+                         match the request batch; unmatched callers will see a mismatch error"
+                    );
+                }
+
+                let mut responses = responses.into_iter();
+                for tx in senders {
+                    match responses.next() {
+                        Some(rsp) => {
+                            let _ = tx.send(Ok(rsp));
+                        }
+                        None => {
+                            let _ = tx.send(Err(ServiceError::new(
+                                BatchLengthMismatch::new().into(),
+                            )));
+                        }
+                    }
+                }
+
+                // Asserted only after every caller has been answered, so a
+                // debug build still delivers the mismatch errors above.
+                debug_assert!(
+                    !mismatched,
+                    "batched service response count must match request count"
+                );
+            }
+            Err(e) => {
+                let error = ServiceError::new(e.into());
+                for tx in senders {
+                    let _ = tx.send(Err(error.clone()));
+                }
+            }
+        }
+    }
+}
+
+impl<T, Request> Future for Worker<T, Request>
+where
+    T: Service<Vec<Request>>,
+    T::Response: IntoIterator,
+    T::Error: Into<crate::BoxError>,
+{
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            // Finish driving any batch that's already in flight before doing
+            // anything else with it.
+            if let Some((fut, _)) = self.flushing.as_mut() {
+                let result = ready!(fut.as_mut().poll(cx));
+                let (_, senders) = self.flushing.take().expect("just matched Some");
+                Self::dispatch_result(senders, result);
+                if self.finish && self.batch.is_empty() {
+                    return Poll::Ready(());
+                }
+                continue;
+            }
+
+            match self.poll_fill_batch(cx) {
+                Poll::Ready(()) => {}
+                Poll::Pending => match self.deadline.as_mut() {
+                    Some(deadline) if deadline.as_mut().poll(cx).is_ready() => {
+                        tracing::trace!("batch latency deadline elapsed");
+                    }
+                    _ => return Poll::Pending,
+                },
+            }
+
+            if self.batch.is_empty() {
+                if self.finish {
+                    return Poll::Ready(());
+                }
+                return Poll::Pending;
+            }
+
+            // Check this *before* touching `poll_ready`: once the service has
+            // failed, calling `poll_ready` on it again would violate the
+            // `Service` contract (see `buffer::Worker`'s equivalent check).
+            if self.failed.is_some() {
+                self.fail_batch();
+                continue;
+            }
+
+            match self.service.poll_ready(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Err(e)) => {
+                    self.failed(e.into());
+                    self.fail_batch();
+                    continue;
+                }
+                Poll::Ready(Ok(())) => {}
+            }
+
+            let batch = std::mem::take(&mut self.batch);
+            self.deadline = None;
+            let (requests, senders): (Vec<_>, Vec<_>) =
+                batch.into_iter().map(|msg| (msg.request, msg.tx)).unzip();
+
+            tracing::debug!(batch.len = requests.len(), "flushing batch to inner service");
+            let fut = self.service.call(requests);
+            self.flushing = Some((Box::pin(fut), senders));
+        }
+    }
+}
+
+impl Handle {
+    pub(crate) fn get_error_on_closed(&self) -> crate::BoxError {
+        self.inner
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|svc_err| svc_err.clone().into())
+            .unwrap_or_else(|| Closed::new().into())
+    }
+}
+
+impl Clone for Handle {
+    fn clone(&self) -> Handle {
+        Handle {
+            inner: self.inner.clone(),
+        }
+    }
+}
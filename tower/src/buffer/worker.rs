@@ -1,41 +1,54 @@
 use super::{
-    error::{Closed, ServiceError},
-    message::Message,
+    error::{Closed, RequestTimeout, ServiceError},
+    message::{Message, Tx},
 };
+use std::future::poll_fn;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::{
-    future::Future,
-    pin::Pin,
-    task::{ready, Context, Poll},
-};
-use tokio::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
 use tower_service::Service;
+use tracing::Instrument;
 
-pin_project_lite::pin_project! {
-    /// Task that handles processing the buffer. This type should not be used
-    /// directly, instead `Buffer` requires an `Executor` that can accept this task.
-    ///
-    /// The struct is `pub` in the private module and the type is *not* re-exported
-    /// as part of the public API. This is the "sealed" pattern to include "private"
-    /// types in public traits that are not meant for consumers of the library to
-    /// implement (only call).
-    #[derive(Debug)]
-    pub struct Worker<T, Request>
-    where
-        T: Service<Request>,
-    {
-        rx: mpsc::Receiver<Message<Request, T::Future>>,
-        service: T,
-        finish: bool,
-        failed: Option<ServiceError>,
-        handle: Handle,
-    }
+/// Task that handles processing the buffer. This type should not be used
+/// directly, instead `Buffer` requires an `Executor` that can accept the
+/// future returned by [`Worker::run`].
+///
+/// The struct is `pub` in the private module and the type is *not* re-exported
+/// as part of the public API. This is the "sealed" pattern to include "private"
+/// types in public traits that are not meant for consumers of the library to
+/// implement (only call).
+///
+/// In addition to the usual "run until the last handle drops" shutdown, a
+/// `Worker` can be asked to drain via [`Handle::drain`]: it then stops
+/// pulling new messages off `rx` but keeps servicing everything already
+/// queued before resolving.
+///
+/// [`Worker::run`] spawns each response onto its own task so many requests
+/// can be in flight against the inner service at once, just as forwarding
+/// the raw `T::Future` to the caller did previously. That task races the
+/// caller dropping its end of the `oneshot` (and, if configured, a
+/// per-request timeout) against the response resolving, so a dropped or
+/// timed-out caller still cancels the corresponding work in the inner
+/// service.
+#[derive(Debug)]
+pub struct Worker<T, Request>
+where
+    T: Service<Request>,
+{
+    rx: mpsc::Receiver<Message<Request, T::Response>>,
+    service: T,
+    failed: Option<ServiceError>,
+    handle: Handle,
+    request_timeout: Option<Duration>,
 }
 
 /// Get the error out
 #[derive(Debug)]
 pub(crate) struct Handle {
     inner: Arc<Mutex<Option<ServiceError>>>,
+    draining: Arc<AtomicBool>,
+    drain_notify: Arc<Notify>,
 }
 
 impl<T, Request> Worker<T, Request>
@@ -45,45 +58,79 @@ where
 {
     pub(crate) fn new(
         service: T,
-        rx: mpsc::Receiver<Message<Request, T::Future>>,
+        rx: mpsc::Receiver<Message<Request, T::Response>>,
+        request_timeout: Option<Duration>,
     ) -> (Handle, Worker<T, Request>) {
         let handle = Handle {
             inner: Arc::new(Mutex::new(None)),
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_notify: Arc::new(Notify::new()),
         };
 
         let worker = Worker {
-            finish: false,
             failed: None,
             rx,
             service,
             handle: handle.clone(),
+            request_timeout,
         };
 
         (handle, worker)
     }
 
-    /// Return the next queued Message that hasn't been canceled.
-    ///
-    /// If a `Message` is returned, the `bool` is true if this is the first time we received this
-    /// message, and false otherwise (i.e., we tried to forward it to the backing service before).
-    fn poll_next_msg(&mut self, cx: &mut Context<'_>) -> Poll<Option<Message<Request, T::Future>>> {
-        if self.finish {
-            // We've already received None and are shutting down
-            return Poll::Ready(None);
-        }
+    /// Await the next queued Message that hasn't been canceled.
+    async fn next_msg(&mut self) -> Option<Message<Request, T::Response>> {
+        loop {
+            if self.handle.is_draining() {
+                // We've been asked to drain: stop admitting new requests by
+                // closing the channel so senders see it as closed, rather
+                // than racing them with `try_recv`. `Receiver::close` only
+                // blocks *new* sends from being admitted -- any send that
+                // already acquired a channel permit before we closed it is
+                // still delivered to `recv`, so this still fully services
+                // everything that was already queued.
+                self.rx.close();
 
-        tracing::trace!("worker polling for next message");
-        // Get the next request
-        while let Some(msg) = ready!(Pin::new(&mut self.rx).poll_recv(cx)) {
-            if !msg.tx.is_closed() {
-                tracing::trace!("processing new request");
-                return Poll::Ready(Some(msg));
+                return match self.rx.recv().await {
+                    Some(msg) if !msg.tx.is_closed() => {
+                        tracing::trace!("processing buffered request while draining");
+                        Some(msg)
+                    }
+                    Some(_) => {
+                        tracing::trace!("dropping cancelled request while draining");
+                        continue;
+                    }
+                    None => None,
+                };
             }
-            // Otherwise, request is canceled, so pop the next one.
-            tracing::trace!("dropping cancelled request");
-        }
 
-        Poll::Ready(None)
+            tracing::trace!("worker awaiting next message");
+            // Race the receiver against a drain request so a worker that's
+            // idle (parked here with an empty queue) notices `drain()`
+            // right away instead of only re-checking `is_draining` the next
+            // time a message arrives or the last handle is dropped.
+            tokio::select! {
+                biased;
+
+                _ = self.handle.drain_notify.notified() => {
+                    tracing::trace!("drain requested while idle");
+                    continue;
+                }
+                msg = self.rx.recv() => {
+                    match msg {
+                        Some(msg) if !msg.tx.is_closed() => {
+                            tracing::trace!("processing request");
+                            return Some(msg);
+                        }
+                        Some(_) => {
+                            // Otherwise, request is canceled, so pop the next one.
+                            tracing::trace!("dropping cancelled request");
+                        }
+                        None => return None,
+                    }
+                }
+            }
+        }
     }
 
     fn failed(&mut self, error: crate::BoxError) {
@@ -104,7 +151,7 @@ where
         let mut inner = self.handle.inner.lock().unwrap();
 
         if inner.is_some() {
-            // Future::poll was called after we've already errored out!
+            // `run` was polled after we've already errored out!
             return;
         }
 
@@ -113,65 +160,108 @@ where
 
         self.rx.close();
 
-        // By closing the mpsc::Receiver, we know that poll_next_msg will soon return Ready(None),
-        // which will trigger the `self.finish == true` phase. We just need to make sure that any
-        // requests that we receive before we've exhausted the receiver receive the error:
+        // By closing the mpsc::Receiver, we know that next_msg will soon return None, which ends
+        // `run`. We just need to make sure that any requests that we receive before we've
+        // exhausted the receiver receive the error:
         self.failed = Some(error);
     }
 }
 
-impl<T, Request> Future for Worker<T, Request>
+impl<T, Request> Worker<T, Request>
 where
     T: Service<Request>,
+    T::Future: Send + 'static,
+    T::Response: Send + 'static,
     T::Error: Into<crate::BoxError>,
 {
-    type Output = ();
+    /// Drive this worker to completion, servicing every message it receives
+    /// until the last `Buffer` handle is dropped or a drain is requested,
+    /// fully servicing everything already queued first.
+    ///
+    /// Each accepted request is handed off to its own spawned task (as in
+    /// the baseline, many requests can be in flight against the inner
+    /// service at once -- accepting the next message doesn't wait on the
+    /// previous response). That task races the caller dropping its end of
+    /// the `oneshot` against the response resolving, so cancellation still
+    /// propagates to the inner service exactly as it does when forwarding a
+    /// raw `T::Future`, and, when `request_timeout` is set, against a
+    /// [`tokio::time::sleep`] of that length so a caller observes a timeout error
+    /// instead of waiting forever on a response that never resolves.
+    pub(crate) async fn run(mut self) {
+        loop {
+            if self.failed.is_none() {
+                if let Err(e) = poll_fn(|cx| self.service.poll_ready(cx)).await {
+                    let error = e.into();
+                    tracing::debug!({ %error }, "service failed");
+                    self.failed(error);
+                }
+            }
 
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.finish {
-            return Poll::Ready(());
+            let msg = match self.next_msg().await {
+                Some(msg) => msg,
+                None => {
+                    tracing::trace!("no more requests; worker shutting down");
+                    return;
+                }
+            };
+
+            let span = msg.span.clone();
+            let _guard = span.enter();
+
+            if let Some(ref failed) = self.failed {
+                tracing::trace!("notifying caller about worker failure");
+                let _ = msg.tx.send(Err(failed.clone().into()));
+                continue;
+            }
+
+            tracing::debug!(service.ready = true, message = "processing request");
+            let response = self.service.call(msg.request);
+            let request_timeout = self.request_timeout;
+            drop(_guard);
+
+            // Spawned, not `await`ed here: holding a `Span` `Entered` guard
+            // across an `.await` leaks it into whatever the executor polls
+            // next, so the in-flight work is instrumented with the span
+            // instead of entering it across an await point.
+            tokio::spawn(Self::respond(response, msg.tx, request_timeout).instrument(span));
         }
+    }
 
-        loop {
-            if self.failed.is_none() {
-                match self.service.poll_ready(cx) {
-                    Poll::Pending => {
-                        tracing::trace!(service.ready = false);
-                        return Poll::Pending;
+    /// Drive `response` to completion and deliver its result to `tx`,
+    /// honoring whichever comes first: the caller dropping `tx` (in which
+    /// case `response` is dropped too, canceling any in-progress work in the
+    /// inner service), the configured `request_timeout` elapsing, or the
+    /// response resolving on its own. A failed response carries the inner
+    /// service's own `T::Error`, converted with `Into` but not wrapped in a
+    /// `ServiceError` -- only a whole-worker failure is reported as a
+    /// `ServiceError`.
+    async fn respond(response: T::Future, mut tx: Tx<T::Response>, request_timeout: Option<Duration>) {
+        match request_timeout {
+            Some(timeout) => {
+                tokio::select! {
+                    biased;
+
+                    _ = tx.closed() => {
+                        tracing::trace!("caller dropped request; canceling response");
                     }
-                    Poll::Ready(Err(e)) => {
-                        let error = e.into();
-                        tracing::debug!({ %error }, "service failed");
-                        self.failed(error);
+                    _ = tokio::time::sleep(timeout) => {
+                        let _ = tx.send(Err(RequestTimeout::new().into()));
                     }
-                    Poll::Ready(Ok(())) => {
-                        tracing::debug!(service.ready = true);
+                    result = response => {
+                        let _ = tx.send(result.map_err(Into::into));
                     }
                 }
             }
-            match ready!(self.poll_next_msg(cx)) {
-                Some(msg) => {
-                    let _guard = msg.span.enter();
-                    if let Some(ref failed) = self.failed {
-                        tracing::trace!("notifying caller about worker failure");
-                        let _ = msg.tx.send(Err(failed.clone()));
-                        continue;
-                    }
-
-                    tracing::debug!(service.ready = true, message = "processing request");
-                    let response = self.service.call(msg.request);
+            None => {
+                tokio::select! {
+                    biased;
 
-                    // Send the response future back to the sender.
-                    //
-                    // An error means the request had been canceled in-between
-                    // our calls, the response future will just be dropped.
-                    tracing::trace!("returning response future");
-                    let _ = msg.tx.send(Ok(response));
-                }
-                None => {
-                    // No more more requests _ever_.
-                    self.finish = true;
-                    return Poll::Ready(());
+                    _ = tx.closed() => {
+                        tracing::trace!("caller dropped request; canceling response");
+                    }
+                    result = response => {
+                        let _ = tx.send(result.map_err(Into::into));
+                    }
                 }
             }
         }
@@ -187,12 +277,30 @@ impl Handle {
             .map(|svc_err| svc_err.clone().into())
             .unwrap_or_else(|| Closed::new().into())
     }
+
+    /// Ask the worker to stop accepting new requests and shut down once it
+    /// has finished everything already queued, instead of racing pending
+    /// sends against the last handle being dropped.
+    ///
+    /// Also wakes an idle worker that's parked waiting for the next
+    /// message, so draining takes effect promptly rather than only once
+    /// another message arrives or the last handle is dropped.
+    pub(crate) fn drain(&self) {
+        self.draining.store(true, Ordering::Release);
+        self.drain_notify.notify_waiters();
+    }
+
+    fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
 }
 
 impl Clone for Handle {
     fn clone(&self) -> Handle {
         Handle {
             inner: self.inner.clone(),
+            draining: self.draining.clone(),
+            drain_notify: self.drain_notify.clone(),
         }
     }
 }